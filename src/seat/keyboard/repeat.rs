@@ -1,4 +1,4 @@
-use std::{io, time::Duration};
+use std::{fmt, io, num::NonZeroU32, time::Duration};
 
 use calloop::{
     channel::{self, Channel},
@@ -7,7 +7,7 @@ use calloop::{
 };
 use wayland_client::{
     protocol::{wl_keyboard, wl_seat},
-    Dispatch, QueueHandle,
+    Dispatch, Proxy, QueueHandle,
 };
 
 use super::{
@@ -26,18 +26,174 @@ pub(crate) enum RepeatMessage {
     RepeatInfo(RepeatInfo),
 }
 
+/// Determines how the repeat rate and delay for a [`KeyRepeatSource`] are chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepeatKind {
+    /// Use the repeat rate and delay advertised by the compositor, as sent in the
+    /// `wl_keyboard::repeat_info` event.
+    ///
+    /// This is the behavior most clients want, and matches what the compositor's own UI does.
+    System,
+
+    /// Always repeat at a fixed rate and delay, regardless of what the compositor advertises.
+    ///
+    /// Any `wl_keyboard::repeat_info` sent by the compositor is ignored. This is useful for
+    /// clients such as games or kiosks that want to own their own repeat behavior, or for
+    /// working around compositors that never advertise repeat info at all.
+    Fixed {
+        /// Number of repeats per second.
+        rate: NonZeroU32,
+        /// Delay from the initial press to the first repeat.
+        delay: Duration,
+    },
+}
+
+/// Metadata accompanying each [`KeyEvent`] emitted by a [`KeyRepeatSource`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepeatMetadata {
+    /// Whether this is the first repeat of the held key (i.e. the one fired after `delay`),
+    /// as opposed to a subsequent one spaced by `gap`.
+    pub is_first: bool,
+    /// Number of repeats that have fired for the currently-held key, starting at 0.
+    pub repeat_count: u32,
+}
+
+/// Effectively-infinite duration used to construct the owned [`Timer`] before any key has ever
+/// been pressed; `KeyRepeatSource` starts disarmed, so this deadline is never meant to actually
+/// elapse; the `armed` flag is what really governs whether a repeat is pending.
+const NEVER: Duration = Duration::from_secs(60 * 60 * 24 * 365 * 100);
+
 /// [`EventSource`] used to emit key repeat events.
-#[derive(Debug)]
+///
+/// This combines two underlying sources: the channel half (receiving [`RepeatMessage`]s sent by
+/// the keyboard handler) and an owned [`Timer`] that fires on each repeat. Driving the timer
+/// directly, rather than bouncing it through the channel, means a repeat is delivered as soon as
+/// the loop wakes for it instead of one extra wakeup later.
 pub struct KeyRepeatSource {
     channel: Channel<RepeatMessage>,
     timer: Timer,
-    /// Gap in time to the next key event in milliseconds.
+    /// Whether a key is currently meant to be repeating. The owned `timer` is never removed from
+    /// the loop; while this is `false` its deadline is pushed out to [`NEVER`].
+    armed: bool,
+    /// Gap in time to the next key event, in microseconds.
     gap: u64,
     delay: u64,
     disabled: bool,
     key: Option<KeyEvent>,
+    kind: RepeatKind,
+    /// Consulted on every `StartRepeat`; returning `false` lets the initial press through but
+    /// keeps that key from entering the repeat loop.
+    repeat_predicate: Option<Box<dyn FnMut(&KeyEvent) -> bool>>,
+    /// Number of repeats fired for the currently-held key so far; reset on `StartRepeat`.
+    repeat_count: u32,
+}
+
+impl fmt::Debug for KeyRepeatSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyRepeatSource")
+            .field("gap", &self.gap)
+            .field("delay", &self.delay)
+            .field("disabled", &self.disabled)
+            .field("key", &self.key)
+            .field("kind", &self.kind)
+            .field("repeat_count", &self.repeat_count)
+            .field("armed", &self.armed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl KeyRepeatSource {
+    /// Changes how the repeat rate and delay are determined.
+    ///
+    /// Switching to [`RepeatKind::Fixed`] stores the new rate/delay right away. If a key is
+    /// currently repeating, a repeat the event loop has already scheduled still fires at its
+    /// original time, and the repeats after it are spaced by the new rate; the deadline set here
+    /// only reaches the event loop once this source is next reregistered. Switching to
+    /// [`RepeatKind::System`] takes effect the next time the compositor sends a
+    /// `wl_keyboard::repeat_info` event; until then, the previous rate/delay keep being used.
+    ///
+    /// This never changes the `RepeatInfo::Disable` state. A `Fixed` kind discards every
+    /// `RepeatInfo` that arrives while it is selected, so after switching back to `System` the
+    /// source follows the disabled state it had before `Fixed` was chosen, until the compositor
+    /// next sends `repeat_info`.
+    pub fn set_repeat_kind(&mut self, kind: RepeatKind) {
+        if let RepeatKind::Fixed { rate, delay } = kind {
+            self.gap = gap_for_rate(rate);
+            self.delay = delay.as_millis() as u64;
+
+            if self.key.is_some() {
+                let next = if self.repeat_count == 0 {
+                    Duration::from_millis(self.delay)
+                } else {
+                    Duration::from_micros(self.gap)
+                };
+                self.arm_with(next);
+            }
+        }
+
+        self.kind = kind;
+    }
+
+    /// Sets a predicate controlling which keys are allowed to repeat.
+    ///
+    /// The predicate is consulted once per key press, right before a repeat sequence would be
+    /// armed; returning `false` still delivers the initial press normally, but the key never
+    /// enters the repeat loop. Use [`KeyRepeatSource::clear_repeat_predicate`] to go back to
+    /// repeating every key (the default).
+    pub fn set_repeat_predicate(&mut self, predicate: impl FnMut(&KeyEvent) -> bool + 'static) {
+        self.repeat_predicate = Some(Box::new(predicate));
+    }
+
+    /// Removes any predicate set via [`KeyRepeatSource::set_repeat_predicate`], so every key
+    /// repeats again.
+    pub fn clear_repeat_predicate(&mut self) {
+        self.repeat_predicate = None;
+    }
+
+    /// Stops the timer from firing again until the next [`KeyRepeatSource::arm`]/`arm_with`.
+    fn disarm(&mut self) {
+        self.armed = false;
+        self.timer.set_duration(NEVER);
+    }
+
+    /// (Re)arms the timer, first firing after `self.delay` and then every `self.gap`.
+    fn arm(&mut self) {
+        self.arm_with(Duration::from_millis(self.delay));
+    }
+
+    /// (Re)arms the timer, first firing after `initial` and then every `self.gap`.
+    fn arm_with(&mut self, initial: Duration) {
+        self.armed = true;
+        self.timer.set_duration(initial);
+    }
+}
+
+/// Computes the gap between repeats, in microseconds, for the given rate in repeats per second.
+fn gap_for_rate(rate: NonZeroU32) -> u64 {
+    1_000_000 / rate.get() as u64
 }
 
+/// Version of `wl_keyboard` that introduces the `repeat_info` event.
+///
+/// A `wl_keyboard` created from a `wl_seat` inherits the version the seat itself was bound at
+/// when the registry global was bound — `wl_seat::get_keyboard` has no way to request a
+/// different version for the object it creates. So the only way to actually *get*
+/// `repeat_info` delivered is for the `wl_seat` global to have been bound at
+/// `REPEAT_INFO_SINCE` or higher in the first place; [`SeatState`]'s registry handling should
+/// bind seats at `min(global.version, REPEAT_INFO_SINCE)` or higher. This constant is `pub` so
+/// that binding code can use it rather than duplicating the magic number `4`.
+///
+/// Whatever version the seat ends up bound at, [`RepeatKind::System`] still falls back to a
+/// built-in default rate/delay below, so repeat keeps working even on a seat bound too low.
+pub const REPEAT_INFO_SINCE: u32 = 4;
+
+/// Repeat rate/delay used when the bound seat is too old to ever send `repeat_info`.
+///
+/// These mirror the defaults used by most desktop environments so repeat still feels reasonable
+/// rather than simply not working.
+const FALLBACK_RATE: u32 = 25;
+const FALLBACK_DELAY: Duration = Duration::from_millis(600);
+
 impl SeatState {
     /// Creates a keyboard from a seat.
     ///
@@ -56,6 +212,7 @@ impl SeatState {
         qh: &QueueHandle<D>,
         seat: &wl_seat::WlSeat,
         rmlvo: Option<RMLVO>,
+        repeat_kind: RepeatKind,
     ) -> Result<(wl_keyboard::WlKeyboard, KeyRepeatSource), KeyboardError>
     where
         D: Dispatch<wl_keyboard::WlKeyboard, KeyboardData> + KeyboardHandler + 'static,
@@ -65,7 +222,7 @@ impl SeatState {
             None => KeyboardData::default(),
         };
 
-        self.get_keyboard_with_repeat_with_data(qh, seat, udata)
+        self.get_keyboard_with_repeat_with_data(qh, seat, udata, repeat_kind)
     }
 
     /// Creates a keyboard from a seat.
@@ -77,6 +234,15 @@ impl SeatState {
     /// Typically the compositor will provide a keymap, but you may specify your own keymap using the `rmlvo`
     /// field.
     ///
+    /// This function cannot itself arrange for real `repeat_info` to be delivered: a
+    /// `wl_keyboard` created via `wl_seat::get_keyboard` always inherits the version `seat` was
+    /// bound at by the registry-binding code, with no way to request a different one here. Make
+    /// sure `seat` was itself bound at [`REPEAT_INFO_SINCE`] or higher — that bind has to happen
+    /// wherever the seat global is bound from the registry, not here — if you want real
+    /// `repeat_info` from the compositor. If it wasn't, [`RepeatKind::System`] falls back to a
+    /// built-in default rate/delay instead of leaving repeat silently disabled, but that is only
+    /// a fallback, not a substitute for binding the seat high enough in the first place.
+    ///
     /// ## Errors
     ///
     /// This will return [`SeatError::UnsupportedCapability`] if the seat does not support a keyboard.
@@ -85,6 +251,7 @@ impl SeatState {
         qh: &QueueHandle<D>,
         seat: &wl_seat::WlSeat,
         mut udata: U,
+        repeat_kind: RepeatKind,
     ) -> Result<(wl_keyboard::WlKeyboard, KeyRepeatSource), KeyboardError>
     where
         D: Dispatch<wl_keyboard::WlKeyboard, U> + KeyboardHandler + 'static,
@@ -96,13 +263,35 @@ impl SeatState {
         kbd_data.repeat_sender.replace(repeat_sender);
         kbd_data.init_compose();
 
+        // `seat.get_keyboard` creates the new `wl_keyboard` at the same version the seat was
+        // bound at, so this tells us whether we can ever expect a `repeat_info` event.
+        let system_repeat_supported = seat.version() >= REPEAT_INFO_SINCE;
+
+        let (gap, delay, disabled) = match repeat_kind {
+            RepeatKind::System if system_repeat_supported => (0, 0, true),
+            // The compositor will never send us `repeat_info` on this old a seat; fall back to
+            // a built-in default instead of leaving repeat permanently disabled.
+            RepeatKind::System => (
+                gap_for_rate(NonZeroU32::new(FALLBACK_RATE).unwrap()),
+                FALLBACK_DELAY.as_millis() as u64,
+                false,
+            ),
+            RepeatKind::Fixed { rate, delay } => {
+                (gap_for_rate(rate), delay.as_millis() as u64, false)
+            }
+        };
+
         let repeat = KeyRepeatSource {
             channel,
-            timer: Timer::immediate(),
-            gap: 0,
-            delay: 0,
+            timer: Timer::from_duration(NEVER),
+            armed: false,
+            gap,
+            delay,
             key: None,
-            disabled: true,
+            disabled,
+            kind: repeat_kind,
+            repeat_predicate: None,
+            repeat_count: 0,
         };
 
         Ok((seat.get_keyboard(qh, udata), repeat))
@@ -111,7 +300,7 @@ impl SeatState {
 
 impl EventSource for KeyRepeatSource {
     type Event = KeyEvent;
-    type Metadata = ();
+    type Metadata = RepeatMetadata;
     type Ret = ();
     type Error = io::Error;
 
@@ -126,45 +315,92 @@ impl EventSource for KeyRepeatSource {
     {
         let mut removed = false;
 
-        let timer = &mut self.timer;
-        let gap = &mut self.gap;
-        let delay_mut = &mut self.delay;
-        let key = &mut self.key;
+        // `arm`/`disarm` take `&mut self`, which would conflict with the `&mut self.channel`
+        // borrow below if called from inside the closure. Instead, the closure only touches
+        // individual fields (disjoint from `self.channel`) and records what to do with the
+        // timer in this local, which we act on once the borrow from `process_events` ends.
+        enum TimerAction {
+            None,
+            Arm,
+            Disarm,
+        }
+        let mut timer_action = TimerAction::None;
 
-        // Check if the key repeat should stop
         self.channel
             .process_events(readiness, token, |event, _| {
                 match event {
                     channel::Event::Msg(message) => {
                         match message {
                             RepeatMessage::StopRepeat => {
-                                key.take();
+                                self.key = None;
+                                timer_action = TimerAction::Disarm;
                             }
 
                             RepeatMessage::StartRepeat(mut event) => {
+                                // A new press always stops whatever was previously repeating,
+                                // even if this key turns out not to repeat itself.
+                                self.key = None;
+                                timer_action = TimerAction::Disarm;
+
+                                let should_repeat = self
+                                    .repeat_predicate
+                                    .as_mut()
+                                    .map_or(true, |predicate| predicate(&event));
+
+                                if !should_repeat {
+                                    return;
+                                }
+
                                 // Update time for next event
-                                event.time += *delay_mut as u32;
-                                key.replace(event);
+                                event.time += self.delay as u32;
+                                self.key = Some(event);
+                                self.repeat_count = 0;
 
-                                // Schedule a new press event in the timer.
-                                timer.set_duration(Duration::from_millis(*delay_mut));
+                                // Arming here while system-disabled (e.g. a key pressed before
+                                // the compositor's first `repeat_info`, where `gap`/`delay` are
+                                // still the 0/0 placeholder) would busy-loop the timer at a
+                                // zero-length gap forever, just to keep suppressing the callback.
+                                // Leave it disarmed instead; the `RepeatInfo::Repeat` arm below
+                                // picks this key back up once real timing is known.
+                                let system_disabled =
+                                    self.disabled && matches!(self.kind, RepeatKind::System);
+                                timer_action = if system_disabled {
+                                    TimerAction::Disarm
+                                } else {
+                                    TimerAction::Arm
+                                };
                             }
 
                             RepeatMessage::RepeatInfo(info) => {
+                                // In `Fixed` mode the application has chosen its own rate and
+                                // delay; ignore whatever the compositor advertises.
+                                if matches!(self.kind, RepeatKind::Fixed { .. }) {
+                                    return;
+                                }
+
                                 match info {
                                     // Store the repeat time, using it for the next repeat sequence.
                                     RepeatInfo::Repeat { rate, delay } => {
-                                        // Number of repetitions per second / 1000 ms
-                                        *gap = (rate.get() / 1000) as u64;
-                                        *delay_mut = delay as u64;
+                                        let was_disabled = self.disabled;
+                                        self.gap = gap_for_rate(rate);
+                                        self.delay = delay as u64;
                                         self.disabled = false;
-                                        timer.set_duration(Duration::from_millis(*delay_mut));
+
+                                        // A key held since before this, the compositor's first
+                                        // `repeat_info`, was deliberately left disarmed above to
+                                        // avoid busy-looping; now that real timing is known,
+                                        // start repeating it instead of leaving it stuck until
+                                        // release and repress.
+                                        if was_disabled && self.key.is_some() {
+                                            timer_action = TimerAction::Arm;
+                                        }
                                     }
 
                                     RepeatInfo::Disable => {
                                         // Compositor will send repeat events manually, cancel all repeating events
-                                        key.take();
+                                        self.key = None;
                                         self.disabled = true;
+                                        timer_action = TimerAction::Disarm;
                                     }
                                 }
                             }
@@ -180,22 +416,61 @@ impl EventSource for KeyRepeatSource {
 
         // Keyboard was destroyed
         if removed {
+            self.disarm();
             return Ok(PostAction::Remove);
         }
 
-        timer.process_events(readiness, token, |mut event, _| {
-            if self.disabled || key.is_none() {
-                // TODO How to pause the timer without dropping it?
-                return TimeoutAction::ToDuration(Duration::from_millis(*delay_mut));
+        // `arm`/`disarm` change `self.timer`'s desired deadline, but `Timer::set_duration` does
+        // not retroactively reschedule an already-registered timer by itself — the loop only
+        // picks up the new deadline once it calls our `reregister` again. Track whether that
+        // happened so we can ask for it below.
+        let mut needs_reregister = false;
+        match timer_action {
+            TimerAction::None => {}
+            TimerAction::Arm => {
+                self.arm();
+                needs_reregister = true;
+            }
+            TimerAction::Disarm => {
+                self.disarm();
+                needs_reregister = true;
             }
-            // Invoke the event
-            callback(key.clone().unwrap(), &mut ());
-
-            // Update time for next event
-            event += Duration::from_millis(*gap);
-            // Schedule the next key press
-            TimeoutAction::ToDuration(Duration::from_micros(*gap))
-        })
+        }
+
+        // Drive the repeat callback directly off the owned timer rather than bouncing a message
+        // through the channel: this closure only touches fields disjoint from `self.timer`
+        // (same rule as the channel closure above), so no whole-`self` borrow conflict arises
+        // even though `self.timer.process_events` already holds `&mut self.timer`.
+        let fire_action = self.timer.process_events(readiness, token, |_, _| {
+            if !self.armed {
+                return TimeoutAction::Drop;
+            }
+
+            // `Fixed` mode ignores the compositor's notion of "disabled" entirely, same as it
+            // ignores the rest of `RepeatInfo`.
+            let system_disabled = self.disabled && matches!(self.kind, RepeatKind::System);
+
+            if !system_disabled {
+                if let Some(key) = self.key.clone() {
+                    let mut metadata = RepeatMetadata {
+                        is_first: self.repeat_count == 0,
+                        repeat_count: self.repeat_count,
+                    };
+                    callback(key, &mut metadata);
+                    self.repeat_count += 1;
+                }
+            }
+
+            TimeoutAction::ToDuration(Duration::from_micros(self.gap))
+        })?;
+
+        // Propagate the timer's own request to be reregistered (it asks for this every time it
+        // just rescheduled itself via `TimeoutAction::ToDuration`), in addition to our own.
+        if needs_reregister || matches!(fire_action, PostAction::Reregister) {
+            Ok(PostAction::Reregister)
+        } else {
+            Ok(PostAction::Continue)
+        }
     }
 
     fn register(
@@ -221,3 +496,26 @@ impl EventSource for KeyRepeatSource {
         self.timer.unregister(poll)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gap_for_rate_sub_1000_is_nonzero() {
+        // `gap_for_rate` used to be `rate.get() / 1000`, which truncated to 0 for any rate below
+        // 1000 repeats/sec -- the overwhelming majority of real repeat rates -- and then
+        // rescheduled the timer with `Duration::from_micros(0)`, busy-looping. Regression test
+        // for the fixed `1_000_000 / rate` computation.
+        let rate = NonZeroU32::new(25).unwrap();
+        assert_eq!(gap_for_rate(rate), 40_000);
+        assert_ne!(gap_for_rate(rate), 0);
+    }
+
+    #[test]
+    fn gap_for_rate_matches_expected_interval() {
+        // 33 repeats/sec is roughly what a lot of compositors advertise by default.
+        let rate = NonZeroU32::new(33).unwrap();
+        assert_eq!(gap_for_rate(rate), 1_000_000 / 33);
+    }
+}